@@ -11,12 +11,14 @@ use aws_config::SdkConfig;
 use aws_config::meta::region::RegionProviderChain;
 
 use aws_sdk_s3::{Client, Region};
-use aws_sdk_s3::client::fluent_builders::GetObject;
-use aws_sdk_s3::output::GetObjectOutput;
-use aws_sdk_s3::types::AggregatedBytes;
+use aws_sdk_s3::client::fluent_builders::{GetObject, HeadObject};
+use aws_sdk_s3::output::{CompleteMultipartUploadOutput, GetObjectOutput, HeadObjectOutput, PutObjectOutput};
+use aws_sdk_s3::model::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::types::{AggregatedBytes, ByteStream};
 
 use aws_lambda_events::bytes::Bytes;
-use aws_lambda_events::s3::{S3Entity, S3Event, S3EventRecord};
+use aws_lambda_events::s3::{S3Entity, S3Event};
+use aws_lambda_events::sqs::{BatchItemFailure, SqsBatchResponse, SqsEvent, SqsMessage};
 
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 
@@ -25,9 +27,21 @@ use std::io::Cursor;
 
 use polars::prelude::*;
 
+use urlencoding::decode;
 
+use anyhow::Context as _;
 
+use deltalake::{DeltaTable, DeltaTableBuilder, DeltaTableError};
+use deltalake::arrow::record_batch::RecordBatch;
+use deltalake::operations::create::CreateBuilder;
+use deltalake::writer::{DeltaWriter, RecordBatchWriter};
 
+use std::env;
+
+use clap::Parser;
+
+mod standalone;
+use standalone::{build_sqs_client, run_standalone, Cli};
 
 
 
@@ -36,27 +50,70 @@ use polars::prelude::*;
 
 
 
-/// Lambda response data structure.
-///
-#[derive(Serialize)]
-struct Response {
-    req_id:String,
-    bucket:String,
-    key:String,
-    msg:String,
-}
 
 
 
 
 
 
+/// The reader used to load an object is picked from its reported content type
+/// (or its key's file extension), not its bucket location, so a single
+/// pipeline can ingest CSV, JSON, NDJSON or Parquet drops interchangeably.
+///
+#[derive(Debug, PartialEq)]
+enum ObjectFormat {
+	Csv,
+	Json,
+	NdJson,
+	Parquet,
+}
+
+/// Work out which `ObjectFormat` an object is in. The `content_type` reported
+/// by `head_object` is authoritative when it is one of the types matched
+/// below; when it is missing or any other value (including one carrying
+/// parameters such as `; charset=...`), we fall back to the file extension
+/// on the key. NDJSON is kept distinct from plain JSON
+/// since `JsonReader` needs to be told which shape to expect.
+///
+fn object_format(content_type:Option<&str>, key:&str) -> ObjectFormat {
+	if let Some(content_type) = content_type {
+		match content_type {
+			"application/x-ndjson" => return ObjectFormat::NdJson,
+			"application/json" => return ObjectFormat::Json,
+			"application/vnd.apache.parquet" => return ObjectFormat::Parquet,
+			"application/octet-stream" if key.ends_with(".parquet") => return ObjectFormat::Parquet,
+			"text/csv" => return ObjectFormat::Csv,
+			_ => {},
+		}
+	}
+
+	let key = key.to_lowercase();
+	if key.ends_with(".ndjson") || key.ends_with(".jsonl") {
+		ObjectFormat::NdJson
+	}
+	else if key.ends_with(".json") {
+		ObjectFormat::Json
+	}
+	else if key.ends_with(".parquet") {
+		ObjectFormat::Parquet
+	}
+	else {
+		ObjectFormat::Csv
+	}
+}
+
 /// Download a S3 object given it's key and bucket location, then loads it to a DataFrame.
 ///
 pub async fn read_s3_object(s3_client:Client, bucket:&str, key:&str) -> Result<DataFrame, anyhow::Error> {
     tracing::info!("bucket:      {}", bucket);
     tracing::info!("key:      {}", key);
 
+	let head_request:HeadObject = s3_client.head_object().bucket(bucket).key(key);
+	let head_response:HeadObjectOutput = head_request.clone().send().await.context("head_object failed")?;
+	let format:ObjectFormat = object_format(head_response.content_type(), key);
+
+	tracing::info!("format:      {:?}", format);
+
 	let request:GetObject = s3_client.get_object().bucket(bucket).key(key);
 	let response:GetObjectOutput = request.clone().send().await;
 
@@ -70,7 +127,12 @@ pub async fn read_s3_object(s3_client:Client, bucket:&str, key:&str) -> Result<D
 			tracing::info!("Object is downloaded, size is {}", bytes.len());
 
 			let cursor:Cursor<Bytes> = Cursor::new(bytes);
-			let df:DataFrame = CsvReader::new(cursor).finish().unwrap();
+			let df:DataFrame = match format {
+				ObjectFormat::Csv => CsvReader::new(cursor).finish().unwrap(),
+				ObjectFormat::Json => JsonReader::new(cursor).finish().unwrap(),
+				ObjectFormat::NdJson => JsonReader::new(cursor).with_json_format(JsonFormat::JsonLines).finish().unwrap(),
+				ObjectFormat::Parquet => ParquetReader::new(cursor).finish().unwrap(),
+			};
 
 			Ok(df)
 		},
@@ -80,30 +142,245 @@ pub async fn read_s3_object(s3_client:Client, bucket:&str, key:&str) -> Result<D
 
 
 
-pub async fn write_s3_object(s3_client:&Client, bucket:&str, key:&str, data:&DataFrame) -> Result<PutObjectOutput, SdkError<PutObjectError>> {
+/// Default size, in bytes, above which `write_s3_object` switches from a
+/// single `put_object` to a streaming multipart upload. Configurable via the
+/// `MULTIPART_THRESHOLD_BYTES` env var.
+///
+const DEFAULT_MULTIPART_THRESHOLD_BYTES:usize = 5 * 1024 * 1024;
+
+/// S3 requires every part but the last to be at least 5 MiB, so that's also
+/// the size we flush a part at once a multipart upload is underway.
+///
+const MULTIPART_CHUNK_SIZE_BYTES:usize = 5 * 1024 * 1024;
+
+/// How many rows to serialize to CSV at a time. Keeps a bounded amount of
+/// the `DataFrame` turned into bytes at once, whichever sink ends up being
+/// used, instead of materializing the whole output up front.
+///
+const ROW_CHUNK_SIZE:usize = 100_000;
+
+/// Outcome of `write_s3_object`: a small DataFrame goes through a single
+/// `put_object`, a large one is streamed through a multipart upload.
+///
+pub enum WriteOutcome {
+	Put(PutObjectOutput),
+	Multipart(CompleteMultipartUploadOutput),
+}
+
+/// Serialize `data` to CSV one `ROW_CHUNK_SIZE`-row slice at a time. While
+/// the accumulated bytes stay under `threshold` they're buffered for a single
+/// `put_object`; once a chunk pushes the total past it, a multipart upload is
+/// started, and from then on the buffered bytes are sent as a part each time
+/// at least `MULTIPART_CHUNK_SIZE_BYTES` have collected, with whatever is
+/// left going out as the last part. So at most `threshold` (or one part's
+/// worth) plus one row-chunk of CSV is held in memory at once.
+///
+pub async fn write_s3_object(s3_client:&Client, bucket:&str, key:&str, data:&DataFrame) -> Result<WriteOutcome, anyhow::Error> {
+
+	let threshold:usize = env::var("MULTIPART_THRESHOLD_BYTES")
+		.ok()
+		.and_then(|value| value.parse().ok())
+		.unwrap_or(DEFAULT_MULTIPART_THRESHOLD_BYTES);
+
+	let num_rows:usize = data.height();
+
+	let mut pending:Vec<u8> = Vec::new();
+	let mut multipart:Option<MultipartUpload<'_>> = None;
+	let mut offset:usize = 0;
+
+	loop {
+		let length:usize = ROW_CHUNK_SIZE.min(num_rows.saturating_sub(offset));
+		let is_last_chunk:bool = offset + length >= num_rows;
 
-	//let csv:String = data.write_csv(include_header=true, separator=",", line_terminator="\n",)
+		let mut chunk_df:DataFrame = data.slice(offset as i64, length);
 
-    let csv:String = CsvWriter::new()
-        .has_headers(true)
-        .with_separator(",")
-        .with_quote_char('"')
-        .with_line_terminator("\n")
-        .finish(data)
-        .unwrap();
+		CsvWriter::new(&mut pending)
+			.has_headers(offset == 0)
+			.with_separator(",")
+			.with_quote_char('"')
+			.with_line_terminator("\n")
+			.finish(&mut chunk_df)
+			.unwrap();
+
+		if let Some(upload) = multipart.as_mut() {
+			if pending.len() >= MULTIPART_CHUNK_SIZE_BYTES || is_last_chunk {
+				upload.upload_part(std::mem::take(&mut pending)).await?;
+			}
+		}
+		else if pending.len() > threshold {
+			tracing::info!("Output is past the {} byte threshold, streaming a multipart upload", threshold);
+
+			let mut upload:MultipartUpload<'_> = MultipartUpload::create(s3_client, bucket, key).await?;
+			if pending.len() >= MULTIPART_CHUNK_SIZE_BYTES || is_last_chunk {
+				upload.upload_part(std::mem::take(&mut pending)).await?;
+			}
+			multipart = Some(upload);
+		}
+
+		offset += length;
+
+		if is_last_chunk {
+			break;
+		}
+	}
 
-    // AWS body
-    let body:ByteStream = ByteStream::from(csv).await;
+	match multipart {
+		Some(upload) => Ok(WriteOutcome::Multipart(upload.complete().await?)),
+		None => {
+			let body:ByteStream = ByteStream::from(pending);
 
+			let output:PutObjectOutput = s3_client.put_object()
+				.bucket(bucket)
+				.key(key)
+				.body(body)
+				.send()
+				.await?;
 
-	return s3_client.put_object()
-        .bucket(bucket)
-        .key(key)
-        .body(body.unwrap())
-        .send()
-        .await
+			Ok(WriteOutcome::Put(output))
+		},
+	}
 }
 
+/// Bookkeeping for an in-flight multipart upload, so `write_s3_object` only
+/// ever has to hand it one row-chunk's worth of bytes at a time. Aborts the
+/// upload on any part failure so no orphaned partial upload accrues storage
+/// charges.
+///
+struct MultipartUpload<'a> {
+	s3_client:&'a Client,
+	bucket:&'a str,
+	key:&'a str,
+	upload_id:String,
+	next_part_number:i32,
+	completed_parts:Vec<CompletedPart>,
+}
+
+impl<'a> MultipartUpload<'a> {
+
+	async fn create(s3_client:&'a Client, bucket:&'a str, key:&'a str) -> Result<Self, anyhow::Error> {
+		let create = s3_client.create_multipart_upload()
+			.bucket(bucket)
+			.key(key)
+			.send()
+			.await?;
+
+		let upload_id:String = create.upload_id.unwrap();
+
+		Ok(Self { s3_client, bucket, key, upload_id, next_part_number: 1, completed_parts: Vec::new() })
+	}
+
+	async fn upload_part(&mut self, bytes:Vec<u8>) -> Result<(), anyhow::Error> {
+		let part_number:i32 = self.next_part_number;
+		self.next_part_number += 1;
+
+		let upload_result = self.s3_client.upload_part()
+			.bucket(self.bucket)
+			.key(self.key)
+			.upload_id(&self.upload_id)
+			.part_number(part_number)
+			.body(ByteStream::from(bytes))
+			.send()
+			.await;
+
+		match upload_result {
+			Ok(part) => {
+				self.completed_parts.push(
+					CompletedPart::builder()
+						.e_tag(part.e_tag.unwrap_or_default())
+						.part_number(part_number)
+						.build()
+				);
+
+				Ok(())
+			},
+			Err(err) => {
+				tracing::info!("upload_part failed, aborting multipart upload: {}", err);
+
+				self.s3_client.abort_multipart_upload()
+					.bucket(self.bucket)
+					.key(self.key)
+					.upload_id(&self.upload_id)
+					.send()
+					.await?;
+
+				Err(err.into())
+			},
+		}
+	}
+
+	async fn complete(self) -> Result<CompleteMultipartUploadOutput, anyhow::Error> {
+		let completed_upload = CompletedMultipartUpload::builder()
+			.set_parts(Some(self.completed_parts))
+			.build();
+
+		let output:CompleteMultipartUploadOutput = self.s3_client.complete_multipart_upload()
+			.bucket(self.bucket)
+			.key(self.key)
+			.upload_id(self.upload_id)
+			.multipart_upload(completed_upload)
+			.send()
+			.await?;
+
+		Ok(output)
+	}
+}
+
+
+/// Append a `DataFrame` to the Delta table at `TABLE_PATH`, creating it from
+/// the DataFrame's own schema on first write. Partition columns are read
+/// from the ordered, comma-separated `DELTA_PARTITIONS` env var (e.g.
+/// `year,month`), so each invocation lands its rows in the matching
+/// Hive-style partition directory and adds one new commit to `_delta_log`.
+///
+pub async fn write_delta_table(data:&DataFrame) -> Result<DeltaTable, DeltaTableError> {
+
+	let table_path:String = env::var("TABLE_PATH").expect("TABLE_PATH is not set");
+	let partition_columns:Vec<String> = env::var("DELTA_PARTITIONS")
+		.unwrap_or_default()
+		.split(',')
+		.map(|column| column.trim().to_string())
+		.filter(|column| !column.is_empty())
+		.collect();
+
+	let batches:Vec<RecordBatch> = dataframe_to_record_batches(data);
+	let schema = &batches.first()
+		.ok_or_else(|| DeltaTableError::Generic("DataFrame produced no record batches to write to Delta".to_string()))?
+		.schema();
+
+	let mut table:DeltaTable = match DeltaTableBuilder::from_uri(&table_path).load().await {
+		Ok(table) => table,
+		Err(_) => {
+			tracing::info!("No Delta table found at {}, creating one", table_path);
+
+			CreateBuilder::new()
+				.with_location(&table_path)
+				.with_columns(deltalake::schema::Schema::try_from(schema.as_ref())?.get_fields().clone())
+				.with_partition_columns(partition_columns.clone())
+				.await?
+		},
+	};
+
+	let mut writer:RecordBatchWriter = RecordBatchWriter::for_table(&table)?;
+
+	for batch in batches {
+		writer.write(batch).await?;
+	}
+
+	let version = writer.flush_and_commit(&mut table).await?;
+	tracing::info!("Committed Delta table version {}", version);
+
+	Ok(table)
+}
+
+/// Convert a polars `DataFrame` into the Arrow `RecordBatch`es the
+/// `deltalake` writer expects (it speaks `arrow-rs`, not polars' own arrow
+/// implementation).
+///
+fn dataframe_to_record_batches(data:&DataFrame) -> Vec<RecordBatch> {
+	data.iter_chunks()
+		.map(|chunk| RecordBatch::try_from(chunk).unwrap())
+		.collect()
+}
 
 /// Transform data here.
 ///
@@ -116,68 +393,114 @@ pub fn transform(mut data:&DataFrame) -> DataFrame {
 
 
 
-/// Lambda handler function is called when an S3Event occur. The function is
-/// triggered and given an S3 client and the event object. The function terminate
-/// an return either a Response object or an error.
+/// Run the fetch/transform/write pipeline for a single `S3EventRecord`.
 ///
-pub async fn handler(s3_client: &Client, event: LambdaEvent<S3Event>,) -> Result<(), Error> {
+async fn process_record(s3_client:&Client, s3_event:&S3Entity) -> Result<(), anyhow::Error> {
 
-    let start_time: Instant = Instant::now();
+	let start_time:Instant = Instant::now();
 
-    if event.payload.records.len() == 0 {
-		tracing::info!("Empty S3 event received");
-	}
-	else {
-		tracing::info!(records = ?event.payload.records.len(), "Received request from SQS");
-	}
+	let bucket:String = s3_event.bucket.name.clone().unwrap();
+	let raw_key:String = s3_event.object.key.clone().unwrap();
 
-	let s3_event:Option<S3Entity> = event.payload.records.first().map(|event: &S3EventRecord| event.clone().s3);
+	// S3 event notifications percent-encode the key (spaces become `+`,
+	// unicode is `%`-escaped), so it must be decoded before we call
+	// `get_object` with it.
+	let key:String = decode(&raw_key.replace('+', " ")).unwrap().into_owned();
 
+	let output_bucket:String = env!("OUTPUT_S3_BUCKET", "OUTPUT_S3_BUCKET is not set");
 
-	if let Some(s3_event) = s3_event {
+	tracing::info!("Request is for {} and object {}", bucket, key);
 
-		let bucket:String = s3_event.bucket.name.unwrap();
-		let key:String = s3_event.object.key.unwrap();
+	// Fetch CSV/JSON/Parquet file from S3 and load to DataFrame
+	let mut data:DataFrame = read_s3_object(s3_client.clone(), &bucket, &key).await?;
 
-		let output_bucket:String = env!("OUTPUT_S3_BUCKET", "OUTPUT_S3_BUCKET is not set");
+	// Do ETL transformation
+	data = transform(&data);
 
+	// Prepare output key
+	let output_key:String = key.clone();
 
-		tracing::info!("Request is for {} and object {}", bucket, key);
+	// Write DataFrame to S3
+	write_s3_object(s3_client, &output_bucket, &output_key, &data).await?;
 
-		// Fetch CSV file from S3 and load to DataFrame
-		let mut data:DataFrame  = read_s3_object(s3_client, &bucket, &key);
+	// Also land the batch in the Delta lakehouse sink when TABLE_PATH is
+	// configured, so the same ingest serves both the flat per-object file
+	// and an incremental, queryable table.
+	if env::var("TABLE_PATH").is_ok() {
+		write_delta_table(&data).await?;
+	}
+
+	let elapsed_time:Duration = start_time.elapsed();
+	tracing::info!("Completed Processing Data, {:?} seconds!", elapsed_time);
+
+	Ok(())
+}
+
+/// Parse a single SQS message body as an `S3Event` and run every contained
+/// `S3EventRecord` through the fetch/transform/write pipeline. One message
+/// may batch several S3 event records (e.g. when SNS fans a notification out
+/// to SQS); every record still gets a chance to run even once an earlier one
+/// in the same message fails, so a single bad record doesn't skip its
+/// siblings. The message as a whole is reported as failed (for the runtime
+/// to redrive) if its body cannot be parsed or at least one record failed.
+///
+async fn process_message(s3_client:&Client, message:&SqsMessage) -> Result<(), anyhow::Error> {
 
-		// Do ETL transformation
-		data = transform(&data);
+	let body:String = message.body.clone().unwrap_or_default();
+	let s3_event:S3Event = serde_json::from_str(&body).context("failed to parse message body as an S3Event")?;
 
-		// Prepare output key
-		let output_key:String = "";
+	let mut first_error:Option<anyhow::Error> = None;
+
+	for record in s3_event.records.iter() {
+		if let Err(err) = process_record(s3_client, &record.s3).await {
+			tracing::info!("Record for key {:?} failed: {}", record.s3.object.key, err);
+
+			if first_error.is_none() {
+				first_error = Some(err);
+			}
+		}
+	}
+
+	match first_error {
+		Some(err) => Err(err),
+		None => Ok(()),
+	}
+}
 
-		// Write DataFrame to S3
-		let result:Result<PutObjectOutput, SdkError<PutObjectError>> = write_s3_object(s3_client:, &output_bucket, &output_key, &data);
+/// Lambda handler function, wired to the SQS event source mapping that sits
+/// in front of this pipeline (the same shape `standalone.rs` polls
+/// manually): each `SqsMessage` carries an `S3Event` as its body. Every
+/// message is processed independently, so one bad message cannot abort the
+/// rest of the batch; messages that fail are reported back by their real SQS
+/// `messageId` as a partial-batch-failure, so the event source mapping only
+/// redrives those instead of the whole batch.
+///
+pub async fn handler(s3_client: &Client, event: LambdaEvent<SqsEvent>,) -> Result<SqsBatchResponse, Error> {
 
-       // End the timer
-       let elapsed_time:Duration = start_time.elapsed();
+    if event.payload.records.len() == 0 {
+		tracing::info!("Empty SQS event received");
+	}
+	else {
+		tracing::info!(records = ?event.payload.records.len(), "Received request from SQS");
+	}
 
-        match result {
-			Ok(result) => {
-				tracing::info!("Completed Processing Data, {:?} seconds!", elapsed_time);
+	let mut failures:Vec<BatchItemFailure> = Vec::new();
 
-				// prepare the response
-				let response = Response {
-					req_id: event.context.request_id,
-					msg: format!("Completed Processing Data!"),
-				};
+	for message in event.payload.records.iter() {
+		let message_id:String = message.message_id.clone().unwrap_or_default();
 
-				Ok(response)
+		match process_message(s3_client, message).await {
+			Ok(()) => {
+				tracing::info!("Message {} processed successfully", message_id);
 			},
 			Err(err) => {
-				tracing::info!("SDK Error: {}", err);
-				Err(err)
+				tracing::info!("Message {} failed: {}", message_id, err);
+				failures.push(BatchItemFailure { item_identifier: message_id });
 			},
 		}
 	}
-	Ok(())
+
+	Ok(SqsBatchResponse { batch_item_failures: failures })
 }
 
 
@@ -187,6 +510,12 @@ pub async fn handler(s3_client: &Client, event: LambdaEvent<S3Event>,) -> Result
 ///
 #[tokio::main]
 async fn main() -> Result<(), Error> {
+	// Load a local `.env` when present, for standalone testing; missing in
+	// production, where config comes from the Lambda/container environment.
+	let _ = dotenvy::dotenv();
+
+	let cli:Cli = Cli::parse();
+
 	// Required to enable CloudWatch error logging by the runtime
     tracing_subscriber::fmt()
         // this needs to be set to false, otherwise ANSI color codes will
@@ -204,14 +533,20 @@ async fn main() -> Result<(), Error> {
 	// Initialize a AWS S3 client
     let region_name:&str = env!("AWS_REGION", "AWS_REGION is not set");;
     let region:Region = Region::new(Cow::Borrowed(region_name));
-    let region_provider:RegionProviderChain = RegionProviderChain::default_provider().or_else(region);
+    let region_provider:RegionProviderChain = RegionProviderChain::default_provider().or_else(region.clone());
 
     let config:SdkConfig = aws_config::load_from_env().region(region_provider).await;
     let s3_client:Client = Client::new(&config);
 
+	if cli.standalone {
+		let queue_url:String = cli.queue_url.expect("--queue-url (or QUEUE_URL) is required in standalone mode");
+		let sqs_client = build_sqs_client(region.clone()).await;
+
+		return run_standalone(&s3_client, &sqs_client, &queue_url).await;
+	}
 
 	// call the actual handler of the request
-    run(service_fn(|event: LambdaEvent<S3Event>| {
+    run(service_fn(|event: LambdaEvent<SqsEvent>| {
         handler(&s3_client, event)
     }))
     .await
@@ -0,0 +1,98 @@
+//! Standalone mode runs the same ingest pipeline outside of the Lambda
+//! runtime, long-polling an SQS queue itself instead of being invoked by the
+//! Lambda runtime's SQS event source mapping. This is handy for local testing and for
+//! environments that would rather drive the pipeline from a queue.
+//!
+use aws_sdk_s3::Client as S3Client;
+use aws_sdk_sqs::{Client as SqsClient, Region};
+use aws_sdk_sqs::model::Message;
+
+use lambda_runtime::{Context, Error, LambdaEvent};
+
+use aws_lambda_events::sqs::{SqsEvent, SqsMessage};
+
+use clap::Parser;
+
+use crate::handler;
+
+/// CLI arguments for standalone mode. `.env` is loaded first so these can
+/// also be supplied as environment variables while testing locally.
+///
+#[derive(Parser, Debug)]
+#[command(name = "lambdapipeline")]
+pub struct Cli {
+	/// Run as a standalone SQS poller instead of under the Lambda runtime.
+	#[arg(long, env = "STANDALONE")]
+	pub standalone:bool,
+
+	/// URL of the SQS queue to long-poll in standalone mode.
+	#[arg(long, env = "QUEUE_URL")]
+	pub queue_url:Option<String>,
+}
+
+/// Long-poll `queue_url`, routing each message through the same `handler`
+/// the Lambda runtime's SQS event source mapping would call, and only
+/// deleting the message once it has been processed successfully so failures
+/// are retried via the queue's visibility timeout.
+///
+pub async fn run_standalone(s3_client:&S3Client, sqs_client:&SqsClient, queue_url:&str) -> Result<(), Error> {
+
+	tracing::info!("Starting standalone SQS poller for {}", queue_url);
+
+	loop {
+		let received = sqs_client
+			.receive_message()
+			.queue_url(queue_url)
+			.wait_time_seconds(20)
+			.max_number_of_messages(10)
+			.send()
+			.await?;
+
+		let messages:Vec<Message> = received.messages.unwrap_or_default();
+
+		if messages.is_empty() {
+			continue;
+		}
+
+		for message in messages {
+			// Wrap the raw SQS message the same way the event source mapping
+			// would, so it goes through the exact same `handler` the Lambda
+			// runtime uses — body parsing and per-record dispatch included.
+			let sqs_message = SqsMessage {
+				message_id: message.message_id.clone(),
+				body: message.body.clone(),
+				..Default::default()
+			};
+
+			let event = LambdaEvent::new(SqsEvent { records: vec![sqs_message] }, Context::default());
+
+			match handler(s3_client, event).await {
+				Ok(response) if response.batch_item_failures.is_empty() => {
+					if let Some(receipt_handle) = message.receipt_handle {
+						sqs_client
+							.delete_message()
+							.queue_url(queue_url)
+							.receipt_handle(receipt_handle)
+							.send()
+							.await?;
+					}
+				},
+				Ok(_) => {
+					tracing::info!("Message processing failed, leaving it for retry");
+				},
+				Err(err) => {
+					tracing::info!("Failed to process message, leaving it for retry: {}", err);
+				},
+			}
+		}
+	}
+}
+
+/// Build an SQS client sharing the same region resolution as the S3 client.
+///
+pub async fn build_sqs_client(region:Region) -> SqsClient {
+	let region_provider = aws_config::meta::region::RegionProviderChain::default_provider().or_else(region);
+	let config = aws_config::load_from_env().region(region_provider).await;
+
+	SqsClient::new(&config)
+}